@@ -3,28 +3,222 @@ use std::{
 	io,
 	fs::File,
 	fmt,
+	ops::{Index, IndexMut},
 	error::Error,
 };
 
 pub type Cell = u32;
 pub type Grid = Vec<Vec<Cell>>;
+// Per-site firing counts accumulated over a stabilization (the "odometer").
+pub type Odometer = Vec<Vec<u64>>;
 
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum GridType {
 	Finite,		// Finite rectangular grid with sink all around the grid.
 	Toroidal,	// Toroidal rectangular grid with sink at the top-left node.
 }
 
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Order {
+	RowMajor,		// data[i*cols + j]
+	ColumnMajor,	// data[j*rows + i]
+}
+
+// The lattice a sandpile fires on. Every variant keeps the abelian firing rule:
+// when a site reaches its `threshold()`, it subtracts the threshold and sends
+// one chip to each of that many neighbors; chips aimed off a real boundary are
+// lost to the sink.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Neighborhood {
+	#[default]
+	VonNeumann,	// 4 orthogonal neighbors, threshold 4 (the classic grid sandpile).
+	Moore,		// 8 neighbors including diagonals, threshold 8.
+	// Grid wrapped onto a closed surface: columns form a cylinder (left/right
+	// wrap) and the top and bottom edges are stitched to *each other* with
+	// reversed column orientation, so a chip leaving the top re-enters the bottom
+	// edge (and vice versa) — never the edge it left. The sink is fixed at
+	// (0, 0). This topology defines its own edge-gluing, so it ignores the pile's
+	// `GridType` — build Cube piles as `Finite`; `Toroidal` + `Cube` is rejected
+	// at construction.
+	Cube,
+}
+
+impl Neighborhood {
+	pub fn threshold(self) -> Cell {
+		match self {
+			Neighborhood::VonNeumann | Neighborhood::Cube => 4,
+			Neighborhood::Moore => 8,
+		}
+	}
+
+	// The sites that each receive one chip when `(i, j)` fires. Targets that
+	// fall off a real boundary (or onto the sink) are omitted, so the caller
+	// simply loses `threshold() - neighbors.len()` chips to the sink.
+	fn neighbors(self, pos: (usize, usize), rows: usize, cols: usize, grid_type: GridType) -> Vec<(usize, usize)> {
+		match self {
+			Neighborhood::VonNeumann =>
+				offset_neighbors(&[(-1, 0), (0, -1), (1, 0), (0, 1)], pos, rows, cols, grid_type),
+			Neighborhood::Moore =>
+				offset_neighbors(
+					&[(-1, -1), (-1, 0), (-1, 1), (0, -1), (0, 1), (1, -1), (1, 0), (1, 1)],
+					pos, rows, cols, grid_type),
+			Neighborhood::Cube => cube_neighbors(pos, rows, cols),
+		}
+	}
+}
+
+// Resolve a set of `(di, dj)` offsets around `(i, j)` under the given grid type,
+// dropping targets that leave a `Finite` grid and the sink node of a `Toroidal`
+// one, exactly as the original hard-coded von Neumann logic did.
+fn offset_neighbors(offsets: &[(isize, isize)], (i, j): (usize, usize), rows: usize, cols: usize, grid_type: GridType) -> Vec<(usize, usize)> {
+	let mut out = Vec::with_capacity(offsets.len());
+	for &(di, dj) in offsets {
+		let ri = i as isize + di;
+		let rj = j as isize + dj;
+		let (ni, nj) = match grid_type {
+			GridType::Finite => {
+				if ri < 0 || rj < 0 || ri >= rows as isize || rj >= cols as isize {
+					continue;
+				}
+				(ri as usize, rj as usize)
+			},
+			GridType::Toroidal => {
+				let ni = ri.rem_euclid(rows as isize) as usize;
+				let nj = rj.rem_euclid(cols as isize) as usize;
+				if ni == 0 && nj == 0 {
+					continue;
+				}
+				(ni, nj)
+			},
+		};
+		out.push((ni, nj));
+	}
+	out
+}
+
+// Von Neumann neighbors on the cube surface: a horizontal step wraps the column
+// around the cylinder of side faces, while a vertical step off the top edge
+// crosses onto the bottom edge (and vice versa) with the column mirrored, so the
+// opposite edge is reached in the correct orientation. On a grid of at least two
+// rows and two columns a site is therefore never its own neighbor. The node
+// (0, 0) is the sink.
+fn cube_neighbors((i, j): (usize, usize), rows: usize, cols: usize) -> Vec<(usize, usize)> {
+	let mut out = Vec::with_capacity(4);
+	for &(di, dj) in &[(-1, 0), (0, -1), (1, 0), (0, 1)] {
+		let (ni, nj) = if dj != 0 {
+			// Horizontal: the side faces form a cylinder.
+			(i, (j as isize + dj).rem_euclid(cols as isize) as usize)
+		} else {
+			// Vertical: stitch top and bottom edges together, mirroring the column.
+			let ri = i as isize + di;
+			if ri < 0 {
+				(rows - 1, cols - 1 - j)
+			} else if ri >= rows as isize {
+				(0, cols - 1 - j)
+			} else {
+				(ri as usize, j)
+			}
+		};
+		if ni == 0 && nj == 0 {
+			continue;
+		}
+		out.push((ni, nj));
+	}
+	out
+}
+
+// Flat, cache-friendly backing store for a rectangular grid of cells. A single
+// contiguous buffer is indexed through `(row, column)` pairs, with the linear
+// offset chosen by `order`, so `topple()` no longer chases a pointer per row.
+#[derive(Debug, Clone)]
+struct CellBuf {
+	data: Vec<Cell>,
+	rows: usize,
+	cols: usize,
+	order: Order,
+}
+
+impl CellBuf {
+	fn new(rows: usize, cols: usize, order: Order) -> CellBuf {
+		CellBuf {
+			data: vec![0; rows * cols],
+			rows,
+			cols,
+			order,
+		}
+	}
+
+	fn from_nested(grid: &Grid, order: Order) -> CellBuf {
+		let mut buf = CellBuf::new(grid.len(), grid[0].len(), order);
+		for i in 0..buf.rows {
+			for j in 0..buf.cols {
+				buf[(i, j)] = grid[i][j];
+			}
+		}
+		buf
+	}
+
+	fn to_nested(&self) -> Grid {
+		let mut grid = Vec::with_capacity(self.rows);
+		for i in 0..self.rows {
+			let mut row = Vec::with_capacity(self.cols);
+			for j in 0..self.cols {
+				row.push(self[(i, j)]);
+			}
+			grid.push(row);
+		}
+		grid
+	}
+
+	#[inline]
+	fn offset(&self, (i, j): (usize, usize)) -> usize {
+		match self.order {
+			Order::RowMajor => i * self.cols + j,
+			Order::ColumnMajor => j * self.rows + i,
+		}
+	}
+}
+
+impl Index<(usize, usize)> for CellBuf {
+	type Output = Cell;
+	fn index(&self, idx: (usize, usize)) -> &Cell {
+		&self.data[self.offset(idx)]
+	}
+}
+
+impl IndexMut<(usize, usize)> for CellBuf {
+	fn index_mut(&mut self, idx: (usize, usize)) -> &mut Cell {
+		let o = self.offset(idx);
+		&mut self.data[o]
+	}
+}
+
 #[derive(Debug, Clone)]
 pub struct GridSandpile {
 	grid_type: GridType,
-	grid: Grid,
+	neighborhood: Neighborhood,
+	grid: CellBuf,
 	last_topple: u64,
 }
 
 impl PartialEq for GridSandpile {
 	fn eq(&self, other: &GridSandpile) -> bool {
-		self.grid_type == other.grid_type && self.grid == other.grid
+		if self.grid_type != other.grid_type
+			|| self.grid.rows != other.grid.rows
+			|| self.grid.cols != other.grid.cols {
+			return false;
+		}
+		for i in 0..self.grid.rows {
+			for j in 0..self.grid.cols {
+				if self.grid[(i, j)] != other.grid[(i, j)] {
+					return false;
+				}
+			}
+		}
+		true
 	}
 }
 
@@ -33,9 +227,10 @@ impl Eq for GridSandpile {}
 impl fmt::Display for GridSandpile {
 	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
 		let vis = [" ", ".", ":", "&", "#"];
-		for row in &self.grid {
-			for el in row {
-				write!(f, "{}", vis[if *el < 4 {*el} else {4} as usize])?;
+		for i in 0..self.grid.rows {
+			for j in 0..self.grid.cols {
+				let el = self.grid[(i, j)];
+				write!(f, "{}", vis[if el < 4 {el} else {4} as usize])?;
 			}
 			writeln!(f)?;
 		}
@@ -45,6 +240,36 @@ impl fmt::Display for GridSandpile {
 
 impl GridSandpile {
 	pub fn from_grid(grid_type: GridType, grid: Grid) -> Result<GridSandpile, SandpileError> {
+		GridSandpile::from_grid_with(grid_type, grid, Order::RowMajor, Neighborhood::VonNeumann)
+	}
+
+	// As `from_grid`, but storing the cells in the requested `order`. Pick
+	// `Order::ColumnMajor` when the configuration is going to be walked column
+	// by column, so the hot axis stays contiguous in the flat buffer.
+	pub fn from_grid_ordered(grid_type: GridType, grid: Grid, order: Order) -> Result<GridSandpile, SandpileError> {
+		GridSandpile::from_grid_with(grid_type, grid, order, Neighborhood::VonNeumann)
+	}
+
+	// The fully explicit constructor: choose the storage `order` and the
+	// `neighborhood` (lattice) the pile fires on. `from_grid` and
+	// `from_grid_ordered` are thin wrappers over it that use the von Neumann
+	// neighborhood (and, for `from_grid`, row-major order).
+	pub fn from_grid_with(grid_type: GridType, grid: Grid, order: Order, neighborhood: Neighborhood) -> Result<GridSandpile, SandpileError> {
+		let mut sandpile = GridSandpile::assemble(grid_type, grid, order, neighborhood)?;
+		sandpile.topple();
+		Ok(sandpile)
+	}
+
+	// Validate `grid`, zero the toroidal sink and build the pile, but leave it
+	// *unstable* — the caller is responsible for running `topple` (or
+	// `topple_recorded`). Shared by the toppling and frame-recording paths.
+	fn assemble(grid_type: GridType, grid: Grid, order: Order, neighborhood: Neighborhood) -> Result<GridSandpile, SandpileError> {
+		// The Cube topology supplies its own edge-gluing and sink, so it is only
+		// meaningful on a `Finite` grid; refuse to silently override a caller's
+		// `Toroidal` choice.
+		if grid_type == GridType::Toroidal && neighborhood == Neighborhood::Cube {
+			return Err(SandpileError::IncompatibleTopology(grid_type, neighborhood));
+		}
 		if grid.is_empty() {
 			return Err(SandpileError::EmptyGrid);
 		}
@@ -60,16 +285,26 @@ impl GridSandpile {
 		}
 		let mut sandpile = GridSandpile {
 			grid_type,
-			grid,
+			neighborhood,
+			grid: CellBuf::from_nested(&grid, order),
 			last_topple: 0,
 		};
 		if grid_type == GridType::Toroidal {
-			sandpile.grid[0][0] = 0;
+			sandpile.grid[(0, 0)] = 0;
 		}
-		sandpile.topple();
 		Ok(sandpile)
 	}
 
+	// Build a pile from an unstable configuration and stabilize it, returning the
+	// stable pile together with the sequence of grids — the starting grid, then
+	// one per sweep of the outer loop. Use it to watch an avalanche spread or a
+	// `neutral`/`inverse` fractal emerge, then hand the frames to `png_frames`.
+	pub fn record(grid_type: GridType, grid: Grid, order: Order, neighborhood: Neighborhood) -> Result<(GridSandpile, Vec<Grid>), SandpileError> {
+		let mut sandpile = GridSandpile::assemble(grid_type, grid, order, neighborhood)?;
+		let frames = sandpile.topple_recorded();
+		Ok((sandpile, frames))
+	}
+
 	pub fn from_string(grid_type: GridType, (x, y): (usize, usize), s: String) -> Result<GridSandpile, SandpileError> {
 		let mut g = Vec::new();
 		for line in s.lines() {
@@ -86,12 +321,12 @@ impl GridSandpile {
 			}
 			g.push(row);
 		}
-		if y == 0 || x == 0 || g.len() == 0 {
+		if y == 0 || x == 0 || g.is_empty() {
 			return Err(SandpileError::EmptyGrid);
 		}
 		let s = GridSandpile::from_grid(grid_type, g)?;
-		if s.grid.len() != y || s.grid[0].len() != x {
-			return Err(SandpileError::UnequalDimensions(x, y, s.grid.len(), s.grid[0].len()))
+		if s.grid.rows != y || s.grid.cols != x {
+			return Err(SandpileError::UnequalDimensions(x, y, s.grid.cols, s.grid.rows))
 		}
 		Ok(s)
 	}
@@ -100,44 +335,86 @@ impl GridSandpile {
 		if p.grid_type != self.grid_type {
 			return Err(SandpileError::UnequalTypes(self.grid_type, p.grid_type));
 		}
-		if p.grid.len() != self.grid.len() || p.grid[0].len() != self.grid[0].len() {
+		if p.grid.rows != self.grid.rows || p.grid.cols != self.grid.cols {
 			return Err(SandpileError::UnequalDimensions(
-			self.grid.len(), self.grid[0].len(), p.grid.len(), p.grid[0].len()));
+			self.grid.cols, self.grid.rows, p.grid.cols, p.grid.rows));
 		}
-		for i in 0..self.grid.len() {
-			for j in 0..self.grid[0].len() {
-				self.grid[i][j] += p.grid[i][j];
+		for i in 0..self.grid.rows {
+			for j in 0..self.grid.cols {
+				self.grid[(i, j)] += p.grid[(i, j)];
 			}
 		}
 		self.topple();
 		Ok(())
 	}
-	
-	pub fn neutral(grid_type: GridType, (x, y): (usize, usize)) -> GridSandpile {
+
+	pub fn neutral(grid_type: GridType, dims: (usize, usize)) -> GridSandpile {
+		GridSandpile::neutral_with(grid_type, dims, Neighborhood::VonNeumann)
+	}
+
+	// The neutral (identity) element of the sandpile group on the given lattice.
 	// Proposition 6.36 of http://people.reed.edu/~davidp/divisors_and_sandpiles/
-		let mut sandpile = GridSandpile::from_grid(grid_type, vec![vec![6; x]; y]).unwrap();
-		for row in &mut sandpile.grid {
-			for el in row {
-				*el = 6 - *el;
-			}
+	// generalizes from the von Neumann `6 = 2*4 - 2` to `2*t - 2` for threshold `t`.
+	pub fn neutral_with(grid_type: GridType, (x, y): (usize, usize), neighborhood: Neighborhood) -> GridSandpile {
+		let full = 2 * neighborhood.threshold() - 2;
+		let mut sandpile = GridSandpile::from_grid_with(grid_type, vec![vec![full; x]; y], Order::RowMajor, neighborhood).unwrap();
+		for el in &mut sandpile.grid.data {
+			*el = full - *el;
 		}
 		if grid_type == GridType::Toroidal {
-			sandpile.grid[0][0] = 0;
+			sandpile.grid[(0, 0)] = 0;
 		}
 		sandpile.topple();
 		sandpile
 	}
 
 	pub fn into_grid(self) -> Grid {
-		self.grid
+		self.grid.to_nested()
 	}
 
 	fn topple(&mut self) -> u64 {
+		self.stabilize(false, false).0
+	}
+
+	fn topple_odometer(&mut self, record: bool) -> (u64, Option<Odometer>) {
+		let (count, odometer, _) = self.stabilize(record, false);
+		(count, odometer)
+	}
+
+	// Like `topple`, but captures a snapshot of the grid after each sweep of the
+	// outer stabilization loop. The first frame is the starting configuration and
+	// the last is the stable attractor; intermediate frames may hold cells above
+	// the threshold, which `png_frames` clamps when rendering.
+	pub fn topple_recorded(&mut self) -> Vec<Grid> {
+		self.stabilize(false, true).2.unwrap()
+	}
+
+	// The single stabilization loop shared by `topple`, `topple_odometer` and
+	// `topple_recorded`. When `odometer` is set it tallies per-site firing counts;
+	// when `frames` is set it snapshots the grid before the first sweep and after
+	// every sweep. Keeping one copy means a future change to the firing
+	// rule only has to be made here.
+	fn stabilize(&mut self, odometer: bool, frames: bool) -> (u64, Option<Odometer>, Option<Vec<Grid>>) {
+		let t = self.neighborhood.threshold();
+		// The Cube sink at (0, 0) never holds chips, so it can never fire.
+		if self.neighborhood == Neighborhood::Cube {
+			self.grid[(0, 0)] = 0;
+		}
+		let mut odometer = if odometer {
+			Some(vec![vec![0u64; self.grid.cols]; self.grid.rows])
+		} else {
+			None
+		};
+		let mut frames = if frames {
+			Some(vec![self.grid.to_nested()])
+		} else {
+			None
+		};
 		let mut excessive = HashSet::new();
 		let mut ex2;
-		for i in 0..self.grid.len() {
-			for j in 0..self.grid[i].len() {
-				if self.grid[i][j] >= 4 {
+		for i in 0..self.grid.rows {
+			for j in 0..self.grid.cols {
+				if self.grid[(i, j)] >= t {
 					excessive.insert((i, j));
 				}
 			}
@@ -147,73 +424,74 @@ impl GridSandpile {
 			ex2 = HashSet::new();
 			for c in excessive.drain() {
 				let (i, j) = c;
-				let d = self.grid[i][j] / 4;
+				let d = self.grid[(i, j)] / t;
 				if d == 0 {
 					continue;
 				}
-				self.grid[i][j] %= 4;
+				self.grid[(i, j)] %= t;
 				count += d as u64;
-				let mut topple_to = Vec::new();
-				match self.grid_type {
-					GridType::Finite => {
-						if i > 0 {
-							topple_to.push((i-1, j));
-						}
-						if j > 0 {
-							topple_to.push((i, j-1));
-						}
-						if i < self.grid.len()-1 {
-							topple_to.push((i+1, j));
-						}
-						if j < self.grid[i].len()-1 {
-							topple_to.push((i, j+1));
-						}
-					},
-					GridType::Toroidal => {
-						let i1 = if i > 0 {i-1} else {self.grid.len()-1};
-						if !(i1 == 0 && j == 0) {
-							topple_to.push((i1, j));
-						}
-						let j1 = if j > 0 {j-1} else {self.grid[0].len()-1};
-						if !(i == 0 && j1 == 0) {
-							topple_to.push((i, j1));
-						}
-						let i1 = if i < self.grid.len()-1 {i+1} else {0};
-						if !(i1 == 0 && j == 0) {
-							topple_to.push((i1, j));
-						}
-						let j1 = if j < self.grid[i].len()-1 {j+1} else {0};
-						if !(i == 0 && j1 == 0) {
-							topple_to.push((i, j1));
-						}
-					},
-				};
-				for (ti, tj) in topple_to {
-					self.grid[ti][tj] += d;
-					if self.grid[ti][tj] >= 4 {
+				if let Some(od) = &mut odometer {
+					od[i][j] += d as u64;
+				}
+				for (ti, tj) in self.neighborhood.neighbors((i, j), self.grid.rows, self.grid.cols, self.grid_type) {
+					self.grid[(ti, tj)] += d;
+					if self.grid[(ti, tj)] >= t {
 						ex2.insert((ti, tj));
 					}
 				}
 			}
 			excessive = ex2;
+			if let Some(fr) = &mut frames {
+				fr.push(self.grid.to_nested());
+			}
 		}
 		self.last_topple = count;
-		count
+		(count, odometer, frames)
 	}
-	
+
+	// Drop a single grain at `(row, col)` and stabilize, returning the avalanche
+	// it triggered. Grains are normally dropped on a `Finite` grid so the
+	// avalanche can dissipate into the boundary sink.
+	pub fn drop_grain(&mut self, (i, j): (usize, usize)) -> Avalanche {
+		self.grid[(i, j)] += 1;
+		let (firings, odometer) = self.topple_odometer(true);
+		let odometer = odometer.unwrap();
+		let toppled_cells = odometer.iter().flatten().filter(|&&c| c > 0).count();
+		Avalanche { firings, toppled_cells, odometer }
+	}
+
+	// Repeatedly drop grains, taking each drop position from `pick` (given the
+	// grid dimensions as `(rows, cols)`), and collect the avalanche-size
+	// distribution used to study self-organized criticality. The caller supplies
+	// the source of positions so the crate stays free of a random-number
+	// dependency; pass a uniform RNG to reproduce the classic power-law.
+	pub fn drive<F>(&mut self, grains: u64, mut pick: F) -> AvalancheStats
+	where F: FnMut(usize, usize) -> (usize, usize) {
+		let mut firings = Vec::with_capacity(grains as usize);
+		let mut toppled_cells = Vec::with_capacity(grains as usize);
+		for _ in 0..grains {
+			let pos = pick(self.grid.rows, self.grid.cols);
+			let a = self.drop_grain(pos);
+			firings.push(a.firings);
+			toppled_cells.push(a.toppled_cells);
+		}
+		AvalancheStats { grains, firings, toppled_cells }
+	}
+
 	pub fn last_topple(&self) -> u64 {
 		self.last_topple
 	}
-	
+
 	pub fn inverse(&self) -> GridSandpile {
-		let mut sandpile = GridSandpile::from_grid(self.grid_type, vec![vec![6; self.grid[0].len()]; self.grid.len()]).unwrap();
-		for y in 0..self.grid.len() {
-			for x in 0..self.grid[0].len() {
-				sandpile.grid[y][x] = 2 * (6 - sandpile.grid[y][x]) - self.grid[y][x];
+		let full = 2 * self.neighborhood.threshold() - 2;
+		let mut sandpile = GridSandpile::from_grid_with(self.grid_type, vec![vec![full; self.grid.cols]; self.grid.rows], self.grid.order, self.neighborhood).unwrap();
+		for y in 0..self.grid.rows {
+			for x in 0..self.grid.cols {
+				sandpile.grid[(y, x)] = 2 * (full - sandpile.grid[(y, x)]) - self.grid[(y, x)];
 			}
 		}
 		if self.grid_type == GridType::Toroidal {
-			sandpile.grid[0][0] = 0;
+			sandpile.grid[(0, 0)] = 0;
 		}
 		sandpile.topple();
 		sandpile
@@ -230,10 +508,69 @@ impl GridSandpile {
 		}
 		count
 	}
-	
+
 	pub fn grid_type(&self) -> GridType {
 		self.grid_type
 	}
+
+	pub fn neighborhood(&self) -> Neighborhood {
+		self.neighborhood
+	}
+}
+
+// The result of stabilizing after a single dropped grain.
+#[derive(Debug, Clone)]
+pub struct Avalanche {
+	pub firings: u64,			// total number of individual topplings
+	pub toppled_cells: usize,	// number of distinct sites that fired at least once
+	pub odometer: Odometer,		// per-site firing counts, ready for `odometer_heatmap`
+}
+
+// The avalanche-size distribution gathered by `drive`: one entry per grain.
+#[derive(Debug, Clone)]
+pub struct AvalancheStats {
+	pub grains: u64,
+	pub firings: Vec<u64>,
+	pub toppled_cells: Vec<usize>,
+}
+
+// On-disk form of a sandpile: its type, storage order, neighborhood and the
+// cell contents.
+// Only the data needed to reconstruct the pile is stored; `last_topple` is a
+// property of the toppling that produced the pile, not of the pile itself.
+#[cfg(feature = "serde")]
+#[derive(serde::Serialize, serde::Deserialize)]
+struct SandpileData {
+	grid_type: GridType,
+	order: Order,
+	#[serde(default)]
+	neighborhood: Neighborhood,
+	grid: Grid,
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for GridSandpile {
+	fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+		SandpileData {
+			grid_type: self.grid_type,
+			order: self.grid.order,
+			neighborhood: self.neighborhood,
+			grid: self.grid.to_nested(),
+		}.serialize(serializer)
+	}
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for GridSandpile {
+	fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<GridSandpile, D::Error> {
+		// A stored pile is only valid if it satisfies the toppling invariant, so
+		// we do not trust the cells as read: feeding them back through
+		// `from_grid_with` re-zeros the toroidal sink and re-runs `topple()`,
+		// turning a hand-edited or corrupted file into a valid pile.
+		let data = SandpileData::deserialize(deserializer)?;
+		GridSandpile::from_grid_with(data.grid_type, data.grid, data.order, data.neighborhood)
+			.map_err(serde::de::Error::custom)
+	}
 }
 
 #[derive(Debug)]
@@ -244,6 +581,7 @@ pub enum SandpileError {
 	UnequalTypes(GridType, GridType),
 	UnequalDimensions(usize, usize, usize, usize),
 	UnknownSymbol(char),
+	IncompatibleTopology(GridType, Neighborhood),
 }
 
 impl fmt::Display for SandpileError {
@@ -260,6 +598,8 @@ impl fmt::Display for SandpileError {
 				write!(f, "Incorrect dimensions of sandpile grids: expected {}x{}, got {}x{}.",
 					self_x, self_y, other_x, other_y),
 			SandpileError::UnknownSymbol(ch) => write!(f, "Unknown symbol in the text representation of a sandpile: {}", ch),
+			SandpileError::IncompatibleTopology(grid_type, neighborhood) =>
+				write!(f, "Incompatible grid type and neighborhood: {:?} cannot be combined with {:?}.", grid_type, neighborhood),
 		}
 	}
 }
@@ -288,13 +628,237 @@ pub fn png(grid: &Grid, fname: &str) -> io::Result<()> {
 	let mut p = 0;
 	for row in grid {
 		for el in row {
-			pixels[p..p+4].copy_from_slice(&colors[*el as usize]);
+			pixels[p..p+4].copy_from_slice(&colors[(*el).min(3) as usize]);
 			p += 4;
 		}
 	}
 	repng::encode(File::create(fname)?, grid[0].len() as u32, grid.len() as u32, &pixels)
 }
 
+// Write a recorded stabilization (see `GridSandpile::record` /
+// `topple_recorded`) as a series of numbered PNGs `{prefix}00000.png`,
+// `{prefix}00001.png`, ... reusing the `png` color palette. Cells still above
+// the palette range in an intermediate frame are clamped to the top color.
+pub fn png_frames(frames: &[Grid], prefix: &str) -> io::Result<()> {
+	for (n, frame) in frames.iter().enumerate() {
+		let clamped: Grid = frame.iter()
+			.map(|row| row.iter().map(|&c| c.min(3)).collect())
+			.collect();
+		png(&clamped, &format!("{}{:05}.png", prefix, n))?;
+	}
+	Ok(())
+}
+
+// Collapse an odometer into a four-level grid that `png` can render as a heat
+// map: sites that never fired stay at level 0, the rest are bucketed by firing
+// count relative to the busiest site.
+pub fn odometer_heatmap(odometer: &Odometer) -> Grid {
+	let max = odometer.iter().flatten().copied().max().unwrap_or(0);
+	odometer.iter().map(|row| row.iter().map(|&c| {
+		if c == 0 || max == 0 {
+			0
+		} else {
+			(1 + (c - 1) * 3 / max).min(3) as Cell
+		}
+	}).collect()).collect()
+}
+
+// An abelian sandpile on an arbitrary-dimension rectangular lattice. Dimension
+// `d` is the number of per-axis `extents`; the firing threshold is `2*d` and an
+// unstable site sends one chip along each of its `2*d` axis-neighbors `±e_k`,
+// with boundary loss to the sink (`Finite`) or wraparound onto the origin sink
+// (`Toroidal`), matching `GridSandpile`'s `GridType` semantics. Cells live in a
+// single flat buffer addressed by mixed-radix coordinates.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NdSandpile {
+	grid_type: GridType,
+	extents: Vec<usize>,
+	strides: Vec<usize>,
+	data: Vec<Cell>,
+	last_topple: u64,
+}
+
+impl NdSandpile {
+	// Build a sandpile over `extents` from a flat, row-major (last axis fastest)
+	// buffer of `extents.iter().product()` cells, then stabilize it.
+	pub fn from_cells(grid_type: GridType, extents: Vec<usize>, data: Vec<Cell>) -> Result<NdSandpile, SandpileError> {
+		if extents.is_empty() || extents.contains(&0) {
+			return Err(SandpileError::EmptyGrid);
+		}
+		let total: usize = extents.iter().product();
+		if data.len() != total {
+			return Err(SandpileError::UnequalDimensions(total, 1, data.len(), 1));
+		}
+		let strides = NdSandpile::strides_of(&extents);
+		let mut sandpile = NdSandpile {
+			grid_type,
+			extents,
+			strides,
+			data,
+			last_topple: 0,
+		};
+		if grid_type == GridType::Toroidal {
+			sandpile.data[0] = 0;
+		}
+		sandpile.topple();
+		Ok(sandpile)
+	}
+
+	fn strides_of(extents: &[usize]) -> Vec<usize> {
+		let mut strides = vec![1; extents.len()];
+		for k in (0..extents.len().saturating_sub(1)).rev() {
+			strides[k] = strides[k + 1] * extents[k + 1];
+		}
+		strides
+	}
+
+	pub fn dimension(&self) -> usize {
+		self.extents.len()
+	}
+
+	pub fn extents(&self) -> &[usize] {
+		&self.extents
+	}
+
+	fn threshold(&self) -> Cell {
+		2 * self.extents.len() as Cell
+	}
+
+	fn coords(&self, mut idx: usize) -> Vec<usize> {
+		let mut c = vec![0; self.extents.len()];
+		for (ck, &stride) in c.iter_mut().zip(&self.strides) {
+			*ck = idx / stride;
+			idx %= stride;
+		}
+		c
+	}
+
+	fn index(&self, coord: &[usize]) -> usize {
+		coord.iter().zip(&self.strides).map(|(c, s)| c * s).sum()
+	}
+
+	// Linear indices of the `±e_k` axis-neighbors of `idx`. Targets off a real
+	// boundary (or onto the toroidal origin sink) are dropped, so their chips are
+	// simply lost to the sink.
+	fn neighbors(&self, idx: usize) -> Vec<usize> {
+		let c = self.coords(idx);
+		let mut out = Vec::with_capacity(2 * self.extents.len());
+		for k in 0..self.extents.len() {
+			for &delta in &[-1isize, 1] {
+				let nk = c[k] as isize + delta;
+				let mut nc = c.clone();
+				match self.grid_type {
+					GridType::Finite => {
+						if nk < 0 || nk >= self.extents[k] as isize {
+							continue;
+						}
+						nc[k] = nk as usize;
+						out.push(self.index(&nc));
+					},
+					GridType::Toroidal => {
+						nc[k] = nk.rem_euclid(self.extents[k] as isize) as usize;
+						let ni = self.index(&nc);
+						if ni == 0 {
+							continue;
+						}
+						out.push(ni);
+					},
+				}
+			}
+		}
+		out
+	}
+
+	fn topple(&mut self) -> u64 {
+		let t = self.threshold();
+		let mut excessive = HashSet::new();
+		let mut ex2;
+		for idx in 0..self.data.len() {
+			if self.data[idx] >= t {
+				excessive.insert(idx);
+			}
+		}
+		let mut count = 0;
+		while !excessive.is_empty() {
+			ex2 = HashSet::new();
+			for idx in excessive.drain() {
+				let d = self.data[idx] / t;
+				if d == 0 {
+					continue;
+				}
+				self.data[idx] %= t;
+				count += d as u64;
+				for ni in self.neighbors(idx) {
+					self.data[ni] += d;
+					if self.data[ni] >= t {
+						ex2.insert(ni);
+					}
+				}
+			}
+			excessive = ex2;
+		}
+		self.last_topple = count;
+		count
+	}
+
+	pub fn last_topple(&self) -> u64 {
+		self.last_topple
+	}
+
+	pub fn add(&mut self, p: &NdSandpile) -> Result<(), SandpileError> {
+		if p.grid_type != self.grid_type {
+			return Err(SandpileError::UnequalTypes(self.grid_type, p.grid_type));
+		}
+		if p.extents != self.extents {
+			return Err(SandpileError::UnequalDimensions(self.data.len(), 1, p.data.len(), 1));
+		}
+		for i in 0..self.data.len() {
+			self.data[i] += p.data[i];
+		}
+		self.topple();
+		Ok(())
+	}
+
+	// The neutral (identity) element of the sandpile group on this lattice. As in
+	// the 2D case this fills the lattice with `2*t - 2` (the `6` of the 2D von
+	// Neumann pile generalized to threshold `t = 2*d`), topples, and reflects.
+	pub fn neutral(grid_type: GridType, extents: Vec<usize>) -> NdSandpile {
+		let t = 2 * extents.len() as Cell;
+		let full = 2 * t - 2;
+		let total: usize = extents.iter().product();
+		let mut sandpile = NdSandpile::from_cells(grid_type, extents, vec![full; total]).unwrap();
+		for el in &mut sandpile.data {
+			*el = full - *el;
+		}
+		if grid_type == GridType::Toroidal {
+			sandpile.data[0] = 0;
+		}
+		sandpile.topple();
+		sandpile
+	}
+
+	pub fn inverse(&self) -> NdSandpile {
+		let full = 2 * self.threshold() - 2;
+		let mut sandpile = NdSandpile::from_cells(self.grid_type, self.extents.clone(), vec![full; self.data.len()]).unwrap();
+		for i in 0..self.data.len() {
+			sandpile.data[i] = 2 * (full - sandpile.data[i]) - self.data[i];
+		}
+		if self.grid_type == GridType::Toroidal {
+			sandpile.data[0] = 0;
+		}
+		sandpile.topple();
+		sandpile
+	}
+
+	pub fn into_cells(self) -> Vec<Cell> {
+		self.data
+	}
+
+	pub fn grid_type(&self) -> GridType {
+		self.grid_type
+	}
+}
+
 #[cfg(test)]
 mod tests {
 	use super::*;
@@ -305,14 +869,14 @@ mod tests {
 		let g = s.into_grid();
 		assert_eq!(g, vec![vec![2, 1, 2], vec![2, 1, 2]]);
 	}
-	
+
 	#[test]
 	fn id_torus() {
 		let s = GridSandpile::neutral(GridType::Toroidal, (3, 2));
 		let g = s.into_grid();
 		assert_eq!(g, vec![vec![0, 3, 3], vec![2, 1, 1]]);
 	}
-	
+
 	#[test]
 	fn from_string() {
 		let st = "&. \n:.:\n";
@@ -323,7 +887,7 @@ mod tests {
 		let g = s.into_grid();
 		assert_eq!(g, vec![vec![0, 1, 0], vec![2, 1, 2]]);
 	}
-	
+
 	#[test]
 	fn display() {
 		let g = vec![vec![3, 1, 0], vec![2, 1, 2]];
@@ -332,7 +896,7 @@ mod tests {
 		let s = GridSandpile::from_grid(GridType::Toroidal, g).unwrap();
 		assert_eq!(format!("{}", s), String::from(" . \n:.:\n"));
 	}
-	
+
 	#[test]
 	fn add() {
 		let mut s1 = GridSandpile::from_grid(GridType::Finite, vec![vec![2, 1, 2], vec![3, 3, 1], vec![2, 3, 1]]).unwrap();
@@ -343,17 +907,156 @@ mod tests {
 		assert_eq!(r.last_topple(), 0);
 		assert_eq!(s1.last_topple(), 9);
 	}
-	
+
 	#[test]
 	fn order() {
 		let s = GridSandpile::from_grid(GridType::Finite, vec![vec![3, 3, 3], vec![3, 3, 3]]).unwrap();
 		assert_eq!(s.order(), 7);
 	}
-	
+
 	#[test]
 	fn inverse() {
 		let s = GridSandpile::from_grid(GridType::Finite, vec![vec![3, 3, 3], vec![3, 3, 3]]).unwrap();
 		let i = GridSandpile::from_grid(GridType::Finite, vec![vec![2, 3, 2], vec![2, 3, 2]]).unwrap();
 		assert_eq!(s.inverse(), i);
 	}
+
+	#[test]
+	fn moore_single_fire() {
+		let g = vec![vec![0, 0, 0], vec![0, 8, 0], vec![0, 0, 0]];
+		let s = GridSandpile::from_grid_with(GridType::Finite, g, Order::RowMajor, Neighborhood::Moore).unwrap();
+		assert_eq!(s.into_grid(), vec![vec![1, 1, 1], vec![1, 0, 1], vec![1, 1, 1]]);
+		assert_eq!(Neighborhood::Moore.threshold(), 8);
+	}
+
+	#[test]
+	fn drop_grain_avalanche() {
+		let mut s = GridSandpile::from_grid(GridType::Finite, vec![vec![0, 0, 0], vec![0, 3, 0], vec![0, 0, 0]]).unwrap();
+		let a = s.drop_grain((1, 1));
+		assert_eq!(a.firings, 1);
+		assert_eq!(a.toppled_cells, 1);
+		assert_eq!(a.odometer[1][1], 1);
+		assert_eq!(s.into_grid(), vec![vec![0, 1, 0], vec![1, 0, 1], vec![0, 1, 0]]);
+	}
+
+	#[test]
+	fn drive_collects_distribution() {
+		let mut s = GridSandpile::from_grid(GridType::Finite, vec![vec![3; 3]; 3]).unwrap();
+		let stats = s.drive(4, |_rows, _cols| (1, 1));
+		assert_eq!(stats.grains, 4);
+		assert_eq!(stats.firings.len(), 4);
+		assert_eq!(stats.toppled_cells.len(), 4);
+	}
+
+	#[test]
+	fn odometer_heatmap_levels() {
+		let od = vec![vec![0, 5], vec![1, 3]];
+		assert_eq!(odometer_heatmap(&od), vec![vec![0, 3], vec![1, 2]]);
+	}
+
+	#[test]
+	fn record_frames() {
+		let start = vec![vec![0, 0, 0], vec![0, 4, 0], vec![0, 0, 0]];
+		let stable = vec![vec![0, 1, 0], vec![1, 0, 1], vec![0, 1, 0]];
+		let (s, frames) = GridSandpile::record(GridType::Finite, start.clone(), Order::RowMajor, Neighborhood::VonNeumann).unwrap();
+		assert_eq!(frames.len(), 2);
+		assert_eq!(frames[0], start);
+		assert_eq!(*frames.last().unwrap(), stable);
+		assert_eq!(s.into_grid(), stable);
+	}
+
+	#[test]
+	fn nd_matches_2d_neutral() {
+		// A 2x3 N-dimensional pile is the same object as the 2D (x=3, y=2) one.
+		let nd = NdSandpile::neutral(GridType::Finite, vec![2, 3]);
+		assert_eq!(nd.into_cells(), vec![2, 1, 2, 2, 1, 2]);
+	}
+
+	#[test]
+	fn nd_1d_single_fire() {
+		let s = NdSandpile::from_cells(GridType::Finite, vec![3], vec![0, 2, 0]).unwrap();
+		assert_eq!(s.into_cells(), vec![1, 0, 1]);
+	}
+
+	#[test]
+	fn nd_3d_single_fire() {
+		let mut data = vec![0; 27];
+		data[13] = 6; // center of a 3x3x3 lattice, threshold 2*3 = 6
+		let s = NdSandpile::from_cells(GridType::Finite, vec![3, 3, 3], data).unwrap();
+		let out = s.into_cells();
+		assert_eq!(out[13], 0);
+		assert_eq!(out.iter().filter(|&&c| c == 1).count(), 6);
+		assert_eq!(out.iter().sum::<Cell>(), 6);
+	}
+
+	#[test]
+	fn cube_neighbors_glue() {
+		// Stepping off the top edge crosses to the bottom edge with the column
+		// mirrored; the left step lands on the (0, 0) sink and is dropped; the rest
+		// stay local. A site is never its own neighbor.
+		let n = cube_neighbors((0, 1), 3, 3);
+		assert_eq!(n, vec![(2, 1), (1, 1), (0, 2)]);
+		assert!(!n.contains(&(0, 1)));
+	}
+
+	#[test]
+	fn cube_single_fire() {
+		let g = vec![vec![0, 0, 0], vec![0, 4, 0], vec![0, 0, 0]];
+		let s = GridSandpile::from_grid_with(GridType::Finite, g, Order::RowMajor, Neighborhood::Cube).unwrap();
+		assert_eq!(s.into_grid(), vec![vec![0, 1, 0], vec![1, 0, 1], vec![0, 1, 0]]);
+	}
+
+	#[test]
+	fn cube_neutral_identity() {
+		// The group identity is idempotent: e + e = e.
+		let e = GridSandpile::neutral_with(GridType::Finite, (3, 3), Neighborhood::Cube);
+		assert!(e.clone().into_grid().iter().flatten().all(|&c| c < 4));
+		let mut sum = e.clone();
+		sum.add(&e).unwrap();
+		assert_eq!(sum, e);
+	}
+
+	#[test]
+	fn moore_neutral_identity() {
+		let e = GridSandpile::neutral_with(GridType::Finite, (3, 3), Neighborhood::Moore);
+		assert!(e.clone().into_grid().iter().flatten().all(|&c| c < 8));
+		let mut sum = e.clone();
+		sum.add(&e).unwrap();
+		assert_eq!(sum, e);
+	}
+
+	#[test]
+	fn toroidal_cube_rejected() {
+		let r = GridSandpile::from_grid_with(GridType::Toroidal, vec![vec![0; 3]; 3], Order::RowMajor, Neighborhood::Cube);
+		assert!(r.is_err());
+	}
+
+	#[cfg(feature = "serde")]
+	#[test]
+	fn serde_roundtrip() {
+		let s = GridSandpile::neutral(GridType::Toroidal, (3, 2));
+		let json = serde_json::to_string(&s).unwrap();
+		let s2: GridSandpile = serde_json::from_str(&json).unwrap();
+		assert_eq!(s, s2);
+	}
+
+	#[cfg(feature = "serde")]
+	#[test]
+	fn serde_restabilizes_corrupted() {
+		// A file whose cells are all 9 is not a stable pile; loading it must
+		// topple back to the same attractor as building it from scratch.
+		let json = r#"{"grid_type":"Finite","order":"RowMajor","grid":[[9,9,9],[9,9,9]]}"#;
+		let loaded: GridSandpile = serde_json::from_str(json).unwrap();
+		let built = GridSandpile::from_grid(GridType::Finite, vec![vec![9, 9, 9], vec![9, 9, 9]]).unwrap();
+		assert_eq!(loaded, built);
+	}
+
+	#[test]
+	fn column_major_matches_row_major() {
+		let g = vec![vec![3, 3, 3], vec![3, 3, 3]];
+		let r = GridSandpile::from_grid_ordered(GridType::Finite, g.clone(), Order::RowMajor).unwrap();
+		let c = GridSandpile::from_grid_ordered(GridType::Finite, g, Order::ColumnMajor).unwrap();
+		assert_eq!(r, c);
+		assert_eq!(r.clone().into_grid(), c.into_grid());
+	}
 }